@@ -1,20 +1,92 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use core::{fmt, panic};
 use futures::{stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
-    fs::File,
-    io::{self, BufReader, Read},
+    io::{self, SeekFrom},
     path::PathBuf,
     str::FromStr,
+    time::{Instant, UNIX_EPOCH},
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+use rand::Rng;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    time::{sleep, Duration},
+};
+use tokio_util::io::ReaderStream;
 
-const FILE_SPLIT_SIZE: usize = 50 * 1024 * 1024; // 50MB
+const FILE_SPLIT_SIZE: u64 = 50 * 1024 * 1024; // 50MB part size
+const CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8MiB read window handed to the body
 
 #[derive(Parser)]
 struct Cli {
-    file: std::path::PathBuf,
+    #[command(subcommand)]
+    command: Command,
+    /// Suppress per-part progress output.
+    #[arg(long, global = true)]
+    quiet: bool,
+    /// Emit a single machine-readable JSON result to stdout.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+/// Routes human-readable progress so it never pollutes the `--json` payload on
+/// stdout and stays silent under `--quiet`.
+struct Reporter {
+    quiet: bool,
+    json: bool,
+}
+
+impl Reporter {
+    fn new(quiet: bool, json: bool) -> Self {
+        Self { quiet, json }
+    }
+
+    /// Emit a progress line, unless quieted. Under `--json` it goes to stderr
+    /// so stdout carries only the result object.
+    fn progress(&self, message: impl fmt::Display) {
+        if self.quiet {
+            return;
+        }
+        if self.json {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+}
+
+/// Machine-readable result emitted to stdout under `--json`.
+#[derive(Serialize)]
+struct UploadResult {
+    url: String,
+    key: String,
+    parts: u32,
+    bytes: u64,
+    elapsed_ms: u128,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Upload a file to pdrive.
+    Upload {
+        file: std::path::PathBuf,
+        /// Skip per-part MD5/ETag integrity verification.
+        #[arg(long)]
+        no_verify: bool,
+    },
+    /// Download a file from pdrive by key.
+    Download {
+        key: String,
+        /// Destination path (defaults to the key's file name).
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
 }
 
 #[derive(Serialize, Deserialize)]
@@ -22,6 +94,12 @@ struct Config {
     token: String,
     api_url: String,
     concurrent_requests: u32,
+    #[serde(default = "default_retries")]
+    retries: u32,
+}
+
+fn default_retries() -> u32 {
+    3
 }
 
 impl Default for Config {
@@ -30,6 +108,7 @@ impl Default for Config {
             token: String::from_str("MISSING_TOKEN").unwrap(),
             api_url: String::from_str("MISSING_API").unwrap(),
             concurrent_requests: 2,
+            retries: default_retries(),
         }
     }
 }
@@ -54,47 +133,109 @@ struct R2Multipart {
     upload_id: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct R2Part {
     #[serde(rename = "partNumber")]
     part_number: u32,
     etag: String,
 }
 
-fn split_bytes(path: PathBuf) -> io::Result<Vec<Vec<u8>>> {
-    let mut f = BufReader::new(File::open(path)?);
-    let mut buffer: Vec<u8> = Vec::with_capacity(FILE_SPLIT_SIZE);
-    let mut chunks: Vec<Vec<u8>> = Vec::new();
+/// Per-file resume record persisted to the local sled store. The completed
+/// `R2Part`s are kept under separate keys so concurrent tasks never race on a
+/// single value.
+#[derive(Serialize, Deserialize)]
+struct UploadMeta {
+    key: String,
+    upload_id: String,
+}
+
+/// Open the embedded sled store used to resume interrupted multipart uploads.
+/// It lives next to the confy config file.
+fn open_store() -> Result<sled::Db, Box<dyn Error>> {
+    let mut path = confy::get_configuration_file_path("pdrive", None)?;
+    path.set_file_name("resume.sled");
+    Ok(sled::open(path)?)
+}
 
-    loop {
-        buffer.clear();
-        let n = f
-            .by_ref()
-            .take(FILE_SPLIT_SIZE as u64)
-            .read_to_end(&mut buffer)?;
+/// Stable resume key for a file: path, size and mtime, so editing the file
+/// invalidates any stale record.
+fn state_key(path: &PathBuf, size: u64) -> io::Result<String> {
+    let mtime = path
+        .metadata()?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Ok(format!("{}:{}:{}", path.display(), size, mtime))
+}
+
+/// Number of 50MB parts a file of `size` bytes is split into.
+fn part_count(size: u64) -> u32 {
+    (size.div_ceil(FILE_SPLIT_SIZE)) as u32
+}
+
+/// Exponential backoff (500ms, 1s, 2s …) with up to 50% random jitter so a
+/// burst of failing parts doesn't retry in lockstep.
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = 500u64 * (1 << (attempt - 1));
+    let jitter = rand::thread_rng().gen_range(0..=base / 2);
+    Duration::from_millis(base + jitter)
+}
 
+/// Whether a failed `PUT` response is worth retrying: transient 5xx and 429.
+fn status_is_retryable(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Hex-encode a byte slice, matching the lowercase digest an S3/R2 ETag uses.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// MD5 the `[offset, offset + len)` window of `path`, reading in `CHUNK_SIZE`
+/// windows so the part is never fully buffered.
+async fn part_md5(path: &PathBuf, offset: u64, len: u64) -> io::Result<[u8; 16]> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut reader = file.take(len);
+    let mut hasher = Md5::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
         if n == 0 {
             break;
         }
-
-        chunks.push(buffer.clone());
+        hasher.update(&buf[..n]);
     }
+    Ok(hasher.finalize().into())
+}
 
-    Ok(chunks)
+/// Open `path` at `offset` and hand back a streaming body that yields at most
+/// `len` bytes in `CHUNK_SIZE` windows, so the part never lives in memory all
+/// at once.
+async fn part_body(path: &PathBuf, offset: u64, len: u64) -> io::Result<reqwest::Body> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let reader = file.take(len);
+    Ok(reqwest::Body::wrap_stream(ReaderStream::with_capacity(
+        reader, CHUNK_SIZE,
+    )))
 }
 
-async fn single_upload(path: PathBuf, config: &Config) -> Result<String, Box<dyn Error>> {
+async fn single_upload(
+    path: PathBuf,
+    config: &Config,
+    reporter: &Reporter,
+) -> Result<String, Box<dyn Error>> {
     let client = reqwest::Client::new();
-    let mut file = BufReader::new(File::open(&path)?);
-    let mut buf: Vec<_> = Vec::new();
-
-    let _ = file.read_to_end(&mut buf)?;
+    let file = File::open(&path).await?;
 
-    println!("Uploading...");
+    reporter.progress("Uploading...");
 
+    let body = reqwest::Body::wrap_stream(ReaderStream::with_capacity(file, CHUNK_SIZE));
     let res = client
         .post(config.api_url.clone() + "/upload/" + &path.file_name().unwrap().to_str().unwrap())
-        .body(buf)
+        .body(body)
         .bearer_auth(config.token.clone())
         .send()
         .await?;
@@ -114,58 +255,184 @@ async fn single_upload(path: PathBuf, config: &Config) -> Result<String, Box<dyn
 
 async fn multipart_upload(
     key: String,
-    parts: Vec<Vec<u8>>,
+    path: PathBuf,
+    size: u64,
+    verify: bool,
     config: &Config,
+    reporter: &Reporter,
 ) -> Result<String, Box<dyn Error>> {
-    // Init part
     let client = reqwest::Client::new();
-    // TODO: This request will error weirdly if token is wrong
-    println!("Initializing part upload");
-    let multipart = client
-        .post(config.api_url.clone() + "/upload-part/init/" + &key)
-        .bearer_auth(config.token.clone())
-        .send()
-        .await?
-        .json::<R2Multipart>()
-        .await?;
+    let db = open_store()?;
+    let state_key = state_key(&path, size)?;
+
+    // Resume a previous run if we have a record for this exact file, otherwise
+    // init a fresh multipart upload and remember its key/upload id.
+    let multipart = match db.get(state_key.as_bytes())? {
+        Some(bytes) => {
+            let meta: UploadMeta = serde_json::from_slice(&bytes)?;
+            reporter.progress(format!("Resuming upload {}", meta.upload_id));
+            R2Multipart {
+                key: meta.key,
+                upload_id: meta.upload_id,
+            }
+        }
+        None => {
+            // TODO: This request will error weirdly if token is wrong
+            reporter.progress("Initializing part upload");
+            let multipart = client
+                .post(config.api_url.clone() + "/upload-part/init/" + &key)
+                .bearer_auth(config.token.clone())
+                .send()
+                .await?
+                .json::<R2Multipart>()
+                .await?;
+            let meta = UploadMeta {
+                key: multipart.key.clone(),
+                upload_id: multipart.upload_id.clone(),
+            };
+            db.insert(state_key.as_bytes(), serde_json::to_vec(&meta)?)?;
+            multipart
+        }
+    };
 
-    println!("Uploading parts...");
-    let parts = stream::iter(parts)
-        .enumerate()
-        .map(|(index, part)| {
+    // Parts already persisted from an earlier run are skipped.
+    let part_prefix = format!("{}\0", state_key);
+    let mut done: HashMap<u32, R2Part> = HashMap::new();
+    for item in db.scan_prefix(part_prefix.as_bytes()) {
+        let (_, value) = item?;
+        let part: R2Part = serde_json::from_slice(&value)?;
+        done.insert(part.part_number, part);
+    }
+    let done_numbers: HashSet<u32> = done.keys().copied().collect();
+    let remaining: Vec<u32> = (0..part_count(size))
+        .filter(|index| !done_numbers.contains(&(index + 1)))
+        .collect();
+
+    reporter.progress("Uploading parts...");
+    let path = &path;
+    let db = &db;
+    let part_prefix = &part_prefix;
+    let parts = stream::iter(remaining)
+        .map(|index| {
             let client = &client;
             let multipart = &multipart;
             async move {
-                println!("Uploading part {}", index + 1);
-                let resp = client
-                    .put(
-                        config.api_url.clone()
-                            + "/upload-part/put/"
-                            + &multipart.key
-                            + "/"
-                            + &multipart.upload_id
-                            + "?partNumber="
-                            + &(index + 1).to_string(),
-                    )
-                    .bearer_auth(config.token.clone())
-                    .body(part)
-                    .send()
-                    .await?;
-                println!("Finished uploading part {}", index + 1);
-                if resp.status() != reqwest::StatusCode::OK {
+                let part_number = index + 1;
+                let offset = index as u64 * FILE_SPLIT_SIZE;
+                let len = FILE_SPLIT_SIZE.min(size - offset);
+                let url = config.api_url.clone()
+                    + "/upload-part/put/"
+                    + &multipart.key
+                    + "/"
+                    + &multipart.upload_id
+                    + "?partNumber="
+                    + &part_number.to_string();
+                reporter.progress(format!("Uploading part {}", part_number));
+
+                // The part MD5 is stable across attempts, so hash it once up
+                // front: base64 for the `Content-MD5` header, hex to check the
+                // returned ETag.
+                let digest = if verify {
+                    Some(part_md5(path, offset, len).await?)
+                } else {
+                    None
+                };
+
+                let mut attempt = 0;
+                loop {
+                    attempt += 1;
+                    // A streaming body is single-use, so re-open the part for
+                    // every attempt.
+                    let body = part_body(path, offset, len).await?;
+                    let mut request = client
+                        .put(&url)
+                        .bearer_auth(config.token.clone())
+                        .body(body);
+                    if let Some(digest) = digest {
+                        request = request.header("Content-MD5", STANDARD.encode(digest));
+                    }
+                    let send = request.send().await;
+
+                    let resp = match send {
+                        Ok(resp) => resp,
+                        Err(err) => {
+                            let retryable =
+                                err.is_connect() || err.is_timeout() || err.is_body();
+                            if retryable && attempt < config.retries {
+                                reporter.progress(format!(
+                                    "Part {} failed ({}), retrying (attempt {})",
+                                    part_number, err, attempt
+                                ));
+                                sleep(backoff_delay(attempt)).await;
+                                continue;
+                            }
+                            return Err(Box::new(err) as Box<dyn Error + Send + Sync>);
+                        }
+                    };
+
                     let status = resp.status();
+                    if status == reqwest::StatusCode::OK {
+                        let part = resp.json::<R2Part>().await?;
+                        // Compare the server ETag against the locally computed
+                        // hex digest; a mismatch means a corrupt part.
+                        if let Some(digest) = digest {
+                            let expected = to_hex(&digest);
+                            let got = part.etag.trim_matches('"');
+                            if !got.eq_ignore_ascii_case(&expected) {
+                                if attempt < config.retries {
+                                    reporter.progress(format!(
+                                        "Part {} ETag mismatch, retrying (attempt {})",
+                                        part_number, attempt
+                                    ));
+                                    sleep(backoff_delay(attempt)).await;
+                                    continue;
+                                }
+                                return Err(Box::new(ClientError {
+                                    message: format!(
+                                        "part {} integrity check failed: expected {}, got {}",
+                                        part_number, expected, got
+                                    ),
+                                })
+                                    as Box<dyn Error + Send + Sync>);
+                            }
+                        }
+                        reporter.progress(format!("Finished uploading part {}", part_number));
+                        // Persist progress so an interrupted run can resume.
+                        db.insert(
+                            format!("{}{}", part_prefix, part_number).as_bytes(),
+                            serde_json::to_vec(&part)?,
+                        )?;
+                        db.flush_async().await?;
+                        return Ok::<_, Box<dyn Error + Send + Sync>>(part);
+                    }
+
+                    if status_is_retryable(status) && attempt < config.retries {
+                        reporter.progress(format!(
+                            "Part {} got {}, retrying (attempt {})",
+                            part_number, status, attempt
+                        ));
+                        sleep(backoff_delay(attempt)).await;
+                        continue;
+                    }
+
                     let text = resp.text().await?;
-                    panic!("Unexpected status code ({:?}): {}", status, text)
+                    return Err(Box::new(ClientError {
+                        message: format!("part {} failed ({:?}): {}", part_number, status, text),
+                    }) as Box<dyn Error + Send + Sync>);
                 }
-                resp.json::<R2Part>().await
             }
         })
-        .buffer_unordered(2);
+        .buffer_unordered(config.concurrent_requests as usize);
 
     let uploaded_parts = parts.try_collect::<Vec<_>>().await?;
 
+    // Merge freshly-uploaded parts with the ones restored from the resume
+    // record and order them for the finish call.
+    let mut all_parts: Vec<R2Part> = done.into_values().chain(uploaded_parts).collect();
+    all_parts.sort_by_key(|part| part.part_number);
+
     // Complete part
-    println!("Completing part upload...");
+    reporter.progress("Completing part upload...");
     let complete_resp = client
         .post(
             config.api_url.clone()
@@ -175,37 +442,144 @@ async fn multipart_upload(
                 + &multipart.upload_id,
         )
         .bearer_auth(config.token.clone())
-        .json(&uploaded_parts)
+        .json(&all_parts)
         .send()
         .await?;
 
     match complete_resp.status() {
-        reqwest::StatusCode::OK => Ok(complete_resp.text().await?),
-        _ => {
-            let status = complete_resp.status();
+        reqwest::StatusCode::OK => {
+            // Only a successful finish clears the resume record; otherwise we
+            // keep every uploaded part so a re-run resumes straight to /finish/.
+            clear_state(db, &state_key)?;
+            Ok(complete_resp.text().await?)
+        }
+        status => {
             let text = complete_resp.text().await?;
-            panic!("Unexpected status code ({:?}): {}", status, text);
+            Err(Box::new(ClientError {
+                message: format!("finish failed ({:?}): {}", status, text),
+            }))
+        }
+    }
+}
+
+async fn download(
+    key: String,
+    output: Option<PathBuf>,
+    config: &Config,
+    reporter: &Reporter,
+) -> Result<(), Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let dest = output.unwrap_or_else(|| {
+        std::path::Path::new(&key)
+            .file_name()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(&key))
+    });
+
+    // Resume from whatever is already on disk by asking for the remaining bytes.
+    let existing = tokio::fs::metadata(&dest)
+        .await
+        .map(|meta| meta.len())
+        .unwrap_or(0);
+
+    let mut request = client
+        .get(config.api_url.clone() + "/" + &key)
+        .bearer_auth(config.token.clone());
+    if existing > 0 {
+        reporter.progress(format!("Resuming download of {} from byte {}", key, existing));
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", existing));
+    } else {
+        reporter.progress(format!("Downloading {}", key));
+    }
+    let resp = request.send().await?;
+
+    let mut file = match resp.status() {
+        // The server honored our Range, so append to the partial file.
+        reqwest::StatusCode::PARTIAL_CONTENT => OpenOptions::new().append(true).open(&dest).await?,
+        // Range was ignored (or there was nothing to resume): start fresh.
+        reqwest::StatusCode::OK => File::create(&dest).await?,
+        reqwest::StatusCode::UNAUTHORIZED => {
+            return Err(Box::new(ClientError {
+                message: "Wrong token".to_owned(),
+            }))
+        }
+        status => {
+            let text = resp.text().await?;
+            return Err(Box::new(ClientError {
+                message: format!("unexpected status code ({:?}): {}", status, text),
+            }));
         }
+    };
+
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+
+    reporter.progress(format!("Saved to {}", dest.display()));
+    if reporter.json {
+        let bytes = tokio::fs::metadata(&dest).await.map(|m| m.len()).unwrap_or(0);
+        println!(
+            "{}",
+            serde_json::json!({
+                "key": key,
+                "path": dest.display().to_string(),
+                "bytes": bytes,
+            })
+        );
+    }
+    Ok(())
+}
+
+/// Drop a file's resume record: its meta entry and every persisted part.
+fn clear_state(db: &sled::Db, state_key: &str) -> Result<(), Box<dyn Error>> {
+    db.remove(state_key.as_bytes())?;
+    let part_prefix = format!("{}\0", state_key);
+    for item in db.scan_prefix(part_prefix.as_bytes()) {
+        let (key, _) = item?;
+        db.remove(key)?;
     }
+    db.flush()?;
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // TODO: Add flag for quite mode
     let args = Cli::parse();
     let config: Config = confy::load("pdrive", None)?;
+    let reporter = Reporter::new(args.quiet, args.json);
 
-    let metadata = args.file.metadata()?;
-    let bytes = metadata.len();
-    if bytes <= FILE_SPLIT_SIZE as u64 {
-        let res = single_upload(args.file, &config).await?;
-        println!("{}", config.api_url.clone() + "/" + &res);
-        Ok(())
-    } else {
-        let key = args.file.file_name().unwrap().to_str().unwrap();
-        let splits = split_bytes(args.file.clone())?;
-        let res = multipart_upload(key.to_owned(), splits, &config).await?;
-        println!("{}", config.api_url.clone() + "/" + &res);
-        Ok(())
+    match args.command {
+        Command::Upload { file, no_verify } => {
+            let metadata = file.metadata()?;
+            let bytes = metadata.len();
+            let started = Instant::now();
+            let (res, parts) = if bytes <= FILE_SPLIT_SIZE {
+                (single_upload(file, &config, &reporter).await?, 1)
+            } else {
+                let key = file.file_name().unwrap().to_str().unwrap().to_owned();
+                let res =
+                    multipart_upload(key, file.clone(), bytes, !no_verify, &config, &reporter)
+                        .await?;
+                (res, part_count(bytes))
+            };
+
+            let url = config.api_url.clone() + "/" + &res;
+            if args.json {
+                let result = UploadResult {
+                    url,
+                    key: res,
+                    parts,
+                    bytes,
+                    elapsed_ms: started.elapsed().as_millis(),
+                };
+                println!("{}", serde_json::to_string(&result)?);
+            } else {
+                println!("{}", url);
+            }
+            Ok(())
+        }
+        Command::Download { key, output } => download(key, output, &config, &reporter).await,
     }
 }